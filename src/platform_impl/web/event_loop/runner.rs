@@ -1,9 +1,70 @@
 use super::{backend, state::State};
-use crate::event::{Event, StartCause};
+use crate::event::{Event, StartCause, WindowEvent};
 use crate::event_loop as root;
 
 use instant::{Duration, Instant};
-use std::{cell::RefCell, clone::Clone, collections::VecDeque, rc::Rc};
+use std::{
+    cell::Cell,
+    cell::RefCell,
+    clone::Clone,
+    collections::HashMap,
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, RawWaker, RawWakerVTable, Waker},
+};
+
+// The default `Poll` timeout and the default per-tick event budget, matched to the
+// behaviour this runner had before either became configurable.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const DEFAULT_MAX_EVENTS_PER_TICK: usize = usize::MAX;
+
+// Per-event-type policy for whether a queued `WindowEvent` may be collapsed with an
+// equivalent, still-unhandled one for the same window at the tail of the queue instead of
+// being appended. Defaults to off for every kind, so games that want every pointer sample can keep
+// it that way while UIs opt individual "last-wins" kinds in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CoalescePolicy {
+    pub resized: bool,
+    pub cursor_moved: bool,
+    pub mouse_wheel: bool,
+}
+
+impl CoalescePolicy {
+    // Every kind is enabled, so a run of consecutive same-kind events for a window collapses
+    // to the latest one; suitable for UIs that don't care about intermediate
+    // resize/cursor/scroll samples.
+    pub fn last_wins() -> Self {
+        CoalescePolicy {
+            resized: true,
+            cursor_moved: true,
+            mouse_wheel: true,
+        }
+    }
+
+    fn allows(&self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::Resized(_) => self.resized,
+            WindowEvent::CursorMoved { .. } => self.cursor_moved,
+            WindowEvent::MouseWheel { .. } => self.mouse_wheel,
+            _ => false,
+        }
+    }
+}
+
+// Identifies a custom event source registered with `Shared::register_source`, so it can
+// later be detached with `Shared::unregister`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceToken(usize);
+
+// A `set_interval`/`clear_interval` call, queued for `apply_control_flow` to apply.
+#[derive(Clone, Copy)]
+enum IntervalRequest {
+    None,
+    Set(Duration),
+    Clear,
+}
 
 pub struct Shared<T>(Rc<Execution<T>>);
 
@@ -16,6 +77,35 @@ impl<T> Clone for Shared<T> {
 pub struct Execution<T> {
     runner: RefCell<Option<Runner<T>>>,
     events: RefCell<VecDeque<Event<T>>>,
+    // The timeout used when `ControlFlow::Poll` is selected
+    poll_interval: Cell<Duration>,
+    // The maximum number of events drained from `events` within a single `send_event`
+    // invocation before yielding back to the browser
+    max_events_per_tick: Cell<usize>,
+    // How many events have been drained from `events` so far in the current `send_event` call
+    dispatched_this_tick: Cell<usize>,
+    // Whether the drain hit `max_events_per_tick` during the current `send_event` call; while
+    // set, `apply_control_flow` is skipped unless the handler changed the control flow, since
+    // `resume_timeout` already has a wakeup armed
+    budget_exceeded: Cell<bool>,
+    // Keeps alive the zero-delay timeout used to resume a drain that hit the tick budget
+    resume_timeout: RefCell<Option<backend::Timeout>>,
+    // Which window event kinds get collapsed with the queue tail instead of appended
+    coalesce_policy: Cell<CoalescePolicy>,
+    // The next token handed out by `register_source`
+    next_source_token: Cell<usize>,
+    // Live custom event sources, keyed by their token, with the flag shared with that
+    // source's `push` closure so `unregister` can make it stop delivering instead of merely
+    // forgetting the token.
+    sources: RefCell<HashMap<usize, Rc<Cell<bool>>>>,
+    // Futures spawned via `Shared::spawn`, polled once per `send_event` from the
+    // `EventsCleared` phase
+    tasks: RefCell<Vec<Pin<Box<dyn Future<Output = ()>>>>>,
+    // Keeps alive the zero-delay timeout that gives a freshly spawned task its first poll,
+    // independent of whatever `ControlFlow` is currently selected
+    task_wakeup: RefCell<Option<backend::Timeout>>,
+    // A pending `set_interval`/`clear_interval` call, applied on the next `apply_control_flow`
+    pending_interval: Cell<IntervalRequest>,
 }
 
 struct Runner<T> {
@@ -34,11 +124,53 @@ impl<T: 'static> Runner<T> {
     }
 }
 
+impl<T: 'static> Execution<T> {
+    // `Waker`'s vtable requires thread-safe function pointers but not a `Send`/`Sync` payload;
+    // `Execution` never leaves the wasm main thread, so that's upheld here.
+    const WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+        Self::waker_clone,
+        Self::waker_wake,
+        Self::waker_wake_by_ref,
+        Self::waker_drop,
+    );
+
+    unsafe fn waker_clone(data: *const ()) -> RawWaker {
+        Rc::increment_strong_count(data as *const Execution<T>);
+        RawWaker::new(data, &Self::WAKER_VTABLE)
+    }
+
+    unsafe fn waker_wake(data: *const ()) {
+        Self::waker_wake_by_ref(data);
+        Self::waker_drop(data);
+    }
+
+    unsafe fn waker_wake_by_ref(data: *const ()) {
+        Rc::increment_strong_count(data as *const Execution<T>);
+        let shared = Shared(Rc::from_raw(data as *const Execution<T>));
+        shared.send_event(Event::NewEvents(StartCause::Poll));
+    }
+
+    unsafe fn waker_drop(data: *const ()) {
+        drop(Rc::from_raw(data as *const Execution<T>));
+    }
+}
+
 impl<T: 'static> Shared<T> {
     pub fn new() -> Self {
         Shared(Rc::new(Execution {
             runner: RefCell::new(None),
             events: RefCell::new(VecDeque::new()),
+            poll_interval: Cell::new(DEFAULT_POLL_INTERVAL),
+            max_events_per_tick: Cell::new(DEFAULT_MAX_EVENTS_PER_TICK),
+            dispatched_this_tick: Cell::new(0),
+            budget_exceeded: Cell::new(false),
+            resume_timeout: RefCell::new(None),
+            coalesce_policy: Cell::new(CoalescePolicy::default()),
+            next_source_token: Cell::new(0),
+            sources: RefCell::new(HashMap::new()),
+            tasks: RefCell::new(Vec::new()),
+            task_wakeup: RefCell::new(None),
+            pending_interval: Cell::new(IntervalRequest::None),
         }))
     }
 
@@ -50,6 +182,186 @@ impl<T: 'static> Shared<T> {
         self.send_event(Event::NewEvents(StartCause::Init));
     }
 
+    // Set the timeout used when `ControlFlow::Poll` is selected. Defaults to 1ms.
+    pub fn set_poll_interval(&self, poll_interval: Duration) {
+        self.0.poll_interval.set(poll_interval);
+    }
+
+    // Set the maximum number of queued events drained within a single `send_event` call
+    // before yielding to a zero-delay timeout. Defaults to unbounded. Clamped to at least 1,
+    // since 0 would drain nothing every tick and spin forever.
+    pub fn set_max_events_per_tick(&self, max_events_per_tick: usize) {
+        self.0.max_events_per_tick.set(max_events_per_tick.max(1));
+    }
+
+    // Set which window event kinds may be collapsed into the queue's last entry, when that is
+    // an equivalent event for the same window, instead of being appended. Defaults to
+    // coalescing nothing.
+    pub fn set_coalesce_policy(&self, policy: CoalescePolicy) {
+        self.0.coalesce_policy.set(policy);
+    }
+
+    // Request a recurring `StartCause::Poll` heartbeat every `period`, overriding whatever
+    // `ControlFlow` is picked. Deferred to the next `apply_control_flow`, so safe to call
+    // from within the event handler itself.
+    pub fn set_interval(&self, period: Duration) {
+        self.0.pending_interval.set(IntervalRequest::Set(period));
+    }
+
+    // Stop an active interval heartbeat, falling back to `Wait`. Deferred like `set_interval`.
+    pub fn clear_interval(&self) {
+        self.0.pending_interval.set(IntervalRequest::Clear);
+    }
+
+    // Install a `State::Interval`, replacing whatever state is active. Only called from
+    // `apply_control_flow`, where `runner` isn't already borrowed.
+    fn install_interval(&self, period: Duration) {
+        let cloned = self.clone();
+        let mut state = State::Interval {
+            period,
+            next: Instant::now() + period,
+            timeout: backend::Timeout::new(
+                move || cloned.send_event(Event::NewEvents(StartCause::Poll)),
+                period,
+            ),
+        };
+        if let Some(ref mut runner) = *self.0.runner.borrow_mut() {
+            std::mem::swap(&mut runner.state, &mut state);
+            match state {
+                State::Poll { timeout } | State::WaitUntil { timeout, .. } => timeout.clear(),
+                State::Interval { timeout, .. } => timeout.clear(),
+                _ => (),
+            }
+        }
+    }
+
+    // Leave interval mode, falling back to `Wait`, and return whether an interval was active.
+    // A no-op if no interval is active. Only called from `apply_control_flow`, where `runner`
+    // isn't already borrowed.
+    fn clear_interval_state(&self) -> bool {
+        if let Some(ref mut runner) = *self.0.runner.borrow_mut() {
+            if matches!(runner.state, State::Interval { .. }) {
+                let mut state = State::Wait {
+                    start: Instant::now(),
+                };
+                std::mem::swap(&mut runner.state, &mut state);
+                if let State::Interval { timeout, .. } = state {
+                    timeout.clear();
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    // Register a custom event source. `driver` is called once with a token and a `push`
+    // closure; hang `push` off whatever produces events (a timer, a socket, ...) and its
+    // events will route through `send_event` like any other.
+    pub fn register_source<F>(&self, driver: F) -> SourceToken
+    where
+        F: FnOnce(SourceToken, Box<dyn Fn(Event<T>)>),
+    {
+        let token = SourceToken(self.0.next_source_token.get());
+        self.0.next_source_token.set(token.0 + 1);
+        let live = Rc::new(Cell::new(true));
+        self.0.sources.borrow_mut().insert(token.0, live.clone());
+
+        let cloned = self.clone();
+        driver(
+            token,
+            // `driver` doesn't police itself, so drop events here once unregistered
+            Box::new(move |event| {
+                if live.get() {
+                    cloned.send_event(event);
+                }
+            }),
+        );
+        token
+    }
+
+    // Detach a custom event source, so its push closure stops delivering events
+    pub fn unregister(&self, token: SourceToken) {
+        if let Some(live) = self.0.sources.borrow_mut().remove(&token.0) {
+            live.set(false);
+        }
+    }
+
+    // Whether a custom event source registered via `register_source` is still attached
+    pub fn is_registered(&self, token: SourceToken) -> bool {
+        self.0.sources.borrow().contains_key(&token.0)
+    }
+
+    // Spawn a future, polled from the `EventsCleared` phase of `send_event` and re-polled
+    // whenever its waker fires.
+    pub fn spawn<F>(&self, fut: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.0.tasks.borrow_mut().push(Box::pin(fut));
+        // Give it a first poll even under ControlFlow::Wait, regardless of other activity
+        let cloned = self.clone();
+        self.0.task_wakeup.replace(Some(backend::Timeout::new(
+            move || cloned.send_event(Event::NewEvents(StartCause::Poll)),
+            Duration::from_millis(0),
+        )));
+    }
+
+    fn task_waker(&self) -> Waker {
+        let data = Rc::into_raw(self.0.clone()) as *const ();
+        // Safety: see the note on `Execution::WAKER_VTABLE`.
+        unsafe { Waker::from_raw(RawWaker::new(data, &Execution::<T>::WAKER_VTABLE)) }
+    }
+
+    // Poll every spawned task once, dropping the ones that complete. Pulled out of
+    // `self.0.tasks` first so a task that calls `spawn` from within its own poll doesn't try
+    // to re-borrow the `RefCell`.
+    fn poll_tasks(&self) {
+        let mut tasks: Vec<_> = self.0.tasks.borrow_mut().drain(..).collect();
+        if tasks.is_empty() {
+            return;
+        }
+
+        let waker = self.task_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut pending = Vec::with_capacity(tasks.len());
+        for mut task in tasks.drain(..) {
+            if task.as_mut().poll(&mut cx).is_pending() {
+                pending.push(task);
+            }
+        }
+        self.0.tasks.borrow_mut().extend(pending);
+    }
+
+    // Add an event to the queue, collapsing it into the tail entry instead of appending when
+    // the coalesce policy allows it and the tail is an equivalent event for the same window
+    fn enqueue(&self, event: Event<T>) {
+        if let Event::WindowEvent { window_id, event } = event {
+            if self.0.coalesce_policy.get().allows(&event) {
+                let mut events = self.0.events.borrow_mut();
+                let kind = std::mem::discriminant(&event);
+                if let Some(Event::WindowEvent {
+                    window_id: tail_window_id,
+                    event: tail_event,
+                }) = events.back_mut()
+                {
+                    if *tail_window_id == window_id && std::mem::discriminant(tail_event) == kind
+                    {
+                        *tail_event = event;
+                        return;
+                    }
+                }
+                events.push_back(Event::WindowEvent { window_id, event });
+                return;
+            }
+            self.0
+                .events
+                .borrow_mut()
+                .push_back(Event::WindowEvent { window_id, event });
+            return;
+        }
+        self.0.events.borrow_mut().push_back(event);
+    }
+
     // Add an event to the event loop runner
     //
     // It will determine if the event should be immediately sent to the user or buffered for later
@@ -77,6 +389,7 @@ impl<T: 'static> Shared<T> {
                                 start,
                                 requested_resume: Some(end),
                             },
+                            State::Interval { .. } => StartCause::Poll,
                             State::Exit => {
                                 return;
                             }
@@ -88,10 +401,14 @@ impl<T: 'static> Shared<T> {
             _ => {
                 // Events are currently being handled, so queue this one and don't try to
                 // double-process the event queue
-                self.0.events.borrow_mut().push_back(event);
+                self.enqueue(event);
                 return;
             }
         };
+        // Reset the per-tick event budget: everything from here down is one `send_event`
+        // invocation as far as `handle_event`'s drain is concerned
+        self.0.dispatched_this_tick.set(0);
+        self.0.budget_exceeded.set(false);
         let mut control = self.current_control_flow();
         // Handle starting a new batch of events
         //
@@ -102,7 +419,14 @@ impl<T: 'static> Shared<T> {
             self.handle_event(event, &mut control);
         }
         self.handle_event(Event::EventsCleared, &mut control);
-        self.apply_control_flow(control);
+        self.poll_tasks();
+        // If the drain hit its budget partway through, `resume_timeout` already has a
+        // zero-delay wakeup armed to pick it back up; re-applying an unchanged `control` on top
+        // of that here would arm a second, redundant one (e.g. a `Poll` timeout) for no
+        // benefit, so it is only applied when the handler changed it during this tick.
+        if !self.0.budget_exceeded.get() || control != self.current_control_flow() {
+            self.apply_control_flow(control);
+        }
         // If the event loop is closed, it has been closed this iteration and now the closing
         // event should be emitted
         if self.closed() {
@@ -133,14 +457,37 @@ impl<T: 'static> Shared<T> {
             }
             // If an event is being handled without a runner somehow, add it to the event queue so
             // it will eventually be processed
-            _ => self.0.events.borrow_mut().push_back(event),
+            _ => self.enqueue(event),
         }
 
         // Don't take events out of the queue if the loop is closed or the runner doesn't exist
         // If the runner doesn't exist and this method recurses, it will recurse infinitely
         if !closed && self.0.runner.borrow().is_some() {
+            // Only events actually drained from the queue here count against the per-tick
+            // budget - the three framing `handle_event` calls `send_event` makes directly
+            // (`NewEvents`, the event it was given, `EventsCleared`) are not part of the drain
+            // and must not eat into it.
+            if self.0.events.borrow().is_empty() {
+                return;
+            }
+            if self.0.dispatched_this_tick.get() >= self.0.max_events_per_tick.get() {
+                // The tick's event budget is spent. Leave the rest of the queue in place and,
+                // if there's more to do, re-arm a zero-delay timeout so the drain picks back
+                // up on the next tick instead of starving the browser's main thread.
+                let cloned = self.clone();
+                self.0.resume_timeout.replace(Some(backend::Timeout::new(
+                    move || cloned.send_event(Event::NewEvents(StartCause::Poll)),
+                    Duration::from_millis(0),
+                )));
+                self.0.budget_exceeded.set(true);
+                return;
+            }
+
             // Take an event out of the queue and handle it
             if let Some(event) = self.0.events.borrow_mut().pop_front() {
+                self.0
+                    .dispatched_this_tick
+                    .set(self.0.dispatched_this_tick.get() + 1);
                 self.handle_event(event, control);
             }
         }
@@ -149,13 +496,64 @@ impl<T: 'static> Shared<T> {
     // Apply the new ControlFlow that has been selected by the user
     // Start any necessary timeouts etc
     fn apply_control_flow(&self, control_flow: root::ControlFlow) {
+        // Apply any pending set_interval/clear_interval first
+        match self.0.pending_interval.replace(IntervalRequest::None) {
+            IntervalRequest::Set(period) => {
+                self.install_interval(period);
+                return;
+            }
+            // A cleared interval leaves the runner in `Wait`, unless the handler asked to exit
+            IntervalRequest::Clear => {
+                if self.clear_interval_state() && control_flow != root::ControlFlow::Exit {
+                    return;
+                }
+            }
+            IntervalRequest::None => (),
+        }
+
+        // An active interval re-arms itself with the remaining time to its next deadline
+        // instead of a fresh `period`, so unrelated events can't push it back indefinitely
+        if control_flow != root::ControlFlow::Exit {
+            let current = match *self.0.runner.borrow() {
+                Some(Runner {
+                    state: State::Interval { period, next, .. },
+                    ..
+                }) => Some((period, next)),
+                _ => None,
+            };
+            if let Some((period, next)) = current {
+                let now = Instant::now();
+                let next = if now >= next { now + period } else { next };
+                let delay = next.checked_duration_since(now).unwrap_or(Duration::from_millis(0));
+
+                let cloned = self.clone();
+                let mut state = State::Interval {
+                    period,
+                    next,
+                    timeout: backend::Timeout::new(
+                        move || cloned.send_event(Event::NewEvents(StartCause::Poll)),
+                        delay,
+                    ),
+                };
+                if let Some(ref mut runner) = *self.0.runner.borrow_mut() {
+                    // Swap rather than assign so the superseded timeout is owned here and
+                    // gets cleared, not just dropped, avoiding a ghost tick from the old one
+                    std::mem::swap(&mut runner.state, &mut state);
+                    if let State::Interval { timeout, .. } = state {
+                        timeout.clear();
+                    }
+                }
+                return;
+            }
+        }
+
         let mut control_flow_status = match control_flow {
             root::ControlFlow::Poll => {
                 let cloned = self.clone();
                 State::Poll {
                     timeout: backend::Timeout::new(
                         move || cloned.send_event(Event::NewEvents(StartCause::Poll)),
-                        Duration::from_millis(1),
+                        self.0.poll_interval.get(),
                     ),
                 }
             }
@@ -191,6 +589,7 @@ impl<T: 'static> Shared<T> {
                 std::mem::swap(&mut runner.state, &mut control_flow_status);
                 match control_flow_status {
                     State::Poll { timeout } | State::WaitUntil { timeout, .. } => timeout.clear(),
+                    State::Interval { timeout, .. } => timeout.clear(),
                     _ => (),
                 }
             }
@@ -0,0 +1,46 @@
+use super::backend;
+use crate::event_loop as root;
+
+use instant::{Duration, Instant};
+
+// The runner's current wait/wake strategy, alongside whatever `backend::Timeout` is needed to
+// actually wake it back up.
+pub enum State {
+    Init,
+    Poll {
+        timeout: backend::Timeout,
+    },
+    Wait {
+        start: Instant,
+    },
+    WaitUntil {
+        start: Instant,
+        end: Instant,
+        timeout: backend::Timeout,
+    },
+    // A recurring `Shared::set_interval` heartbeat. `next` only advances when the timeout
+    // actually fires, so in-between events can re-arm it with the remaining time, not a fresh `period`.
+    Interval {
+        period: Duration,
+        next: Instant,
+        timeout: backend::Timeout,
+    },
+    Exit,
+}
+
+impl State {
+    pub fn is_exit(&self) -> bool {
+        matches!(self, State::Exit)
+    }
+}
+
+impl From<&State> for root::ControlFlow {
+    fn from(state: &State) -> Self {
+        match state {
+            State::Init | State::Poll { .. } | State::Interval { .. } => root::ControlFlow::Poll,
+            State::Wait { .. } => root::ControlFlow::Wait,
+            State::WaitUntil { end, .. } => root::ControlFlow::WaitUntil(*end),
+            State::Exit => root::ControlFlow::Exit,
+        }
+    }
+}